@@ -21,33 +21,272 @@ use bitcoin::{
     BitcoinHash,
     network::message::NetworkMessage,
     network::message_blockdata::{GetHeadersMessage,GetBlocksMessage, InvType, Inventory},
-    blockdata::block::{Block, LoneBlockHeader},
+    network::message_filter::{GetCFilters, CFilter, GetCFHeaders, CFHeaders, GetCFCheckpt, CFCheckpt},
+    network::message_bloom::{FilterLoad, FilterAdd},
+    network::message_compact_blocks::{SendCmpct, CmpctBlock, HeaderAndShortIds, PrefilledTransaction,
+        GetBlockTxn, BlockTransactionsRequest, BlockTxn, BlockTransactions},
+    blockdata::block::{Block, BlockHeader, LoneBlockHeader},
+    blockdata::script::Instruction,
+    blockdata::transaction::{Transaction, OutPoint},
     util::hash::Sha256dHash,
+    util::merkleblock::MerkleBlock,
+    consensus::encode,
     consensus::encode::VarInt
 };
+use bitcoin_hashes::sha256;
 use blockfilter::{COIN_FILTER, SCRIPT_FILTER};
 use chaindb::SharedChainDB;
 use chaindb::StoredFilter;
+use configdb::SharedConfigDB;
 use error::SPVError;
 use p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use rand::RngCore;
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::SocketAddr,
     sync::mpsc,
     thread
 };
 
+// per-peer outstanding block bytes above which we stop serving blocks until the queue drains
+const PEER_BYTE_CEILING: usize = 8 * 1024 * 1024;
+
+// filter headers are checkpointed every 1000 blocks, as in Bitcoin Core
+const CFCHECKPT_INTERVAL: u32 = 1000;
+// cap on how many blocks a single GetCFilters/GetCFHeaders/GetCFCheckpt may walk, so a request
+// near the tip can't force an O(chain-height) walk and filter lookup per call
+const MAX_FILTER_RANGE: u32 = 20_000;
+
+// BIP37 hard limits on a filterload, to bound the CPU/memory a single peer can make us spend
+const MAX_BLOOM_HASH_FUNCS: u32 = 50;
+const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+// BIP37 caps a single filteradd element at the largest possible script push
+const MAX_FILTER_ADD_DATA_SIZE: usize = 520;
+
+/// BIP37 bloom update flags
+#[allow(unused)]
+const BLOOM_UPDATE_NONE: u8 = 0;
+const BLOOM_UPDATE_ALL: u8 = 1;
+const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+
+// a connection bloom filter as defined in BIP37
+struct BloomFilter {
+    data: Vec<u8>,
+    n_hash_funcs: u32,
+    n_tweak: u32,
+    flags: u8
+}
+
+impl BloomFilter {
+    fn new(load: &FilterLoad) -> BloomFilter {
+        BloomFilter {
+            data: load.filter.clone(),
+            n_hash_funcs: load.hash_funcs,
+            n_tweak: load.tweak,
+            flags: load.flags
+        }
+    }
+
+    // BIP37 hash: MurmurHash3 with seed = n*0xFBA4C795 + tweak, mapped into the bit array
+    fn bit_index(&self, n: u32, data: &[u8]) -> usize {
+        let seed = (n.wrapping_mul(0xFBA4C795)).wrapping_add(self.n_tweak);
+        (murmur3_32(seed, data) as usize) % (self.data.len() * 8)
+    }
+
+    fn contains(&self, data: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        (0..self.n_hash_funcs).all(|n| {
+            let idx = self.bit_index(n, data);
+            self.data[idx / 8] & (1 << (idx % 8)) != 0
+        })
+    }
+
+    fn insert(&mut self, data: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.n_hash_funcs {
+            let idx = self.bit_index(n, data);
+            self.data[idx / 8] |= 1 << (idx % 8);
+        }
+    }
+
+    // does this transaction match the filter, updating it per BLOOM_UPDATE_* as outputs match
+    fn matches(&mut self, tx: &Transaction) -> bool {
+        let mut matched = self.contains(tx.bitcoin_hash().as_bytes());
+
+        for input in tx.input.iter() {
+            if self.contains(&encode::serialize(&input.previous_output).unwrap()) {
+                matched = true;
+            }
+        }
+
+        let txid = tx.bitcoin_hash();
+        for (vout, output) in tx.output.iter().enumerate() {
+            for instruction in output.script_pubkey.iter(false) {
+                if let Instruction::PushBytes(data) = instruction {
+                    if self.contains(data) {
+                        matched = true;
+                        if self.flags == BLOOM_UPDATE_ALL ||
+                            (self.flags == BLOOM_UPDATE_P2PUBKEY_ONLY &&
+                                (output.script_pubkey.is_p2pk() || output.script_pubkey.is_multisig())) {
+                            let outpoint = OutPoint { txid, vout: vout as u32 };
+                            self.insert(&encode::serialize(&outpoint).unwrap());
+                        }
+                    }
+                }
+            }
+        }
+        matched
+    }
+}
+
+// 32-bit MurmurHash3, as used by BIP37
+fn murmur3_32(seed: u32, data: &[u8]) -> u32 {
+    let c1: u32 = 0xcc9e2d51;
+    let c2: u32 = 0x1b873593;
+    let mut h1 = seed;
+
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k1 = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k1 = k1.wrapping_mul(c1);
+        k1 = k1.rotate_left(15);
+        k1 = k1.wrapping_mul(c2);
+        h1 ^= k1;
+        h1 = h1.rotate_left(13);
+        h1 = h1.wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    let mut k1: u32 = 0;
+    for (i, b) in tail.iter().enumerate() {
+        k1 ^= (*b as u32) << (8 * i);
+    }
+    if !tail.is_empty() {
+        k1 = k1.wrapping_mul(c1);
+        k1 = k1.rotate_left(15);
+        k1 = k1.wrapping_mul(c2);
+        h1 ^= k1;
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85ebca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2ae35);
+    h1 ^= h1 >> 16;
+    h1
+}
+
+// tracks how many block bytes are presently queued for a peer's send channel. `bytes` is the
+// actual outstanding queue depth as last reported by the p2p send thread via
+// PeerMessage::SendQueueBytes, bumped locally when we hand it more to send so back-pressure is
+// felt immediately rather than only after the next report arrives
+struct PeerSendBudget {
+    bytes: usize
+}
+
+// negotiated BIP152 compact block state for a connected peer
+struct CompactPeerState {
+    // 1 = legacy short ids (txid), 2+ = witness-aware short ids (wtxid), per BIP152
+    version: u64,
+    #[allow(unused)]
+    high_bandwidth: bool
+}
+
+// SipHash-2-4, as used by BIP152 to derive short transaction ids
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v0: u64 = 0x736f6d6570736575 ^ k0;
+    let mut v1: u64 = 0x646f72616e646f6d ^ k1;
+    let mut v2: u64 = 0x6c7967656e657261 ^ k0;
+    let mut v3: u64 = 0x7465646279746573 ^ k1;
+
+    macro_rules! round {
+        () => {
+            v0 = v0.wrapping_add(v1); v1 = v1.rotate_left(13); v1 ^= v0; v0 = v0.rotate_left(32);
+            v2 = v2.wrapping_add(v3); v3 = v3.rotate_left(16); v3 ^= v2;
+            v0 = v0.wrapping_add(v3); v3 = v3.rotate_left(21); v3 ^= v0;
+            v2 = v2.wrapping_add(v1); v1 = v1.rotate_left(17); v1 ^= v2; v2 = v2.rotate_left(32);
+        }
+    }
+
+    let chunks = data.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mi = u64::from_le_bytes([chunk[0],chunk[1],chunk[2],chunk[3],chunk[4],chunk[5],chunk[6],chunk[7]]);
+        v3 ^= mi;
+        round!(); round!();
+        v0 ^= mi;
+    }
+
+    let mut last = [0u8; 8];
+    last[..tail.len()].copy_from_slice(tail);
+    last[7] = data.len() as u8;
+    let mi = u64::from_le_bytes(last);
+    v3 ^= mi;
+    round!(); round!();
+    v0 ^= mi;
+
+    v2 ^= 0xff;
+    round!(); round!(); round!(); round!();
+
+    v0 ^ v1 ^ v2 ^ v3
+}
+
+// the two SipHash keys for a block, derived from SHA256(header || nonce) as in BIP152
+fn short_id_keys(header: &BlockHeader, nonce: u64) -> (u64, u64) {
+    let mut ser = encode::serialize(header).unwrap();
+    ser.extend_from_slice(&nonce.to_le_bytes());
+    let digest = sha256::Hash::hash(&ser).into_inner();
+    let k0 = u64::from_le_bytes([digest[0],digest[1],digest[2],digest[3],digest[4],digest[5],digest[6],digest[7]]);
+    let k1 = u64::from_le_bytes([digest[8],digest[9],digest[10],digest[11],digest[12],digest[13],digest[14],digest[15]]);
+    (k0, k1)
+}
+
+// BIP152 version 1 peers identify transactions by legacy txid (witness stripped); version 2+
+// peers negotiated witness-aware relay and must be matched by wtxid instead, since their mempool
+// short-id table is keyed that way
+fn short_id(version: u64, k0: u64, k1: u64, tx: &Transaction) -> u64 {
+    let id = if version >= 2 {
+        Sha256dHash::from_data(&encode::serialize(tx).unwrap())
+    } else {
+        tx.bitcoin_hash()
+    };
+    siphash24(k0, k1, id.as_bytes()) & 0x0000_ffff_ffff_ffff
+}
+
 pub struct BlockServer {
     p2p: P2PControlSender,
     chaindb: SharedChainDB,
+    configdb: SharedConfigDB,
+    // bloom filters loaded by peers that want merkleblocks instead of full blocks (BIP37)
+    filters: RefCell<HashMap<PeerId, BloomFilter>>,
+    // peers that negotiated BIP152 compact block relay
+    compact: RefCell<HashMap<PeerId, CompactPeerState>>,
+    // per-peer byte budget for queued block sends, protecting us against resource-exhaustion requests
+    send_budget: RefCell<HashMap<PeerId, PeerSendBudget>>,
+    // address of each connected peer, so misbehavior can be charged against its addrman entry
+    peer_addrs: RefCell<HashMap<PeerId, SocketAddr>>,
 }
 
 // channel size
 const BACK_PRESSURE: usize = 10;
 
 impl BlockServer {
-    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender) -> PeerMessageSender {
+    pub fn new(chaindb: SharedChainDB, configdb: SharedConfigDB, p2p: P2PControlSender) -> PeerMessageSender {
         let (sender, receiver) = mpsc::sync_channel(BACK_PRESSURE);
 
-        let mut block_server = BlockServer { chaindb, p2p };
+        let mut block_server = BlockServer {
+            chaindb, configdb, p2p,
+            filters: RefCell::new(HashMap::new()),
+            compact: RefCell::new(HashMap::new()),
+            send_budget: RefCell::new(HashMap::new()),
+            peer_addrs: RefCell::new(HashMap::new())
+        };
 
         thread::spawn(move || { block_server.run(receiver) });
 
@@ -56,30 +295,99 @@ impl BlockServer {
 
     fn run(&mut self, receiver: PeerMessageReceiver) {
         while let Ok(msg) = receiver.recv() {
-            if let Err(e) = match msg {
+            match msg {
+                PeerMessage::Connected(pid, addr) => {
+                    self.peer_addrs.borrow_mut().insert(pid, addr);
+                }
+                PeerMessage::Disconnected(pid) => {
+                    self.peer_addrs.borrow_mut().remove(&pid);
+                    self.filters.borrow_mut().remove(&pid);
+                    self.compact.borrow_mut().remove(&pid);
+                    self.send_budget.borrow_mut().remove(&pid);
+                }
+                PeerMessage::SendQueueBytes(pid, bytes) => {
+                    self.note_send_queue_bytes(pid, bytes);
+                }
+                PeerMessage::Latency(pid, ms) => {
+                    self.note_latency(pid, ms);
+                }
                 PeerMessage::Message(pid, msg) => {
-                    match msg {
+                    let result = match msg {
                         NetworkMessage::GetHeaders(get) => self.get_headers(pid, get),
                         NetworkMessage::GetBlocks(get) => self.get_blocks(pid, get),
                         NetworkMessage::GetData(get) => self.get_data(pid, get),
+                        NetworkMessage::GetCFilters(get) => self.get_cfilters(pid, get),
+                        NetworkMessage::GetCFHeaders(get) => self.get_cfheaders(pid, get),
+                        NetworkMessage::GetCFCheckpt(get) => self.get_cfcheckpt(pid, get),
+                        NetworkMessage::FilterLoad(load) => self.filter_load(pid, load),
+                        NetworkMessage::FilterAdd(add) => self.filter_add(pid, add),
+                        NetworkMessage::FilterClear => self.filter_clear(pid),
+                        NetworkMessage::SendCmpct(cmpct) => self.send_cmpct(pid, cmpct),
+                        NetworkMessage::GetBlockTxn(get) => self.get_block_txn(pid, get),
                         _ => { Ok(()) }
+                    };
+                    if let Err(e) = result {
+                        self.note_misbehavior(pid, &e);
+                        error!("Error processing headers: {}", e);
                     }
                 }
-                _ => {Ok(())}
-            } {
-                error!("Error processing headers: {}", e);
+                _ => {}
             }
         }
         panic!("Block server thread failed.");
     }
 
+    // translate a protocol violation surfaced while serving a peer into misbehavior points,
+    // persisted against its addrman entry so repeat offenders get banned there regardless of
+    // whether this particular connection is still open
+    fn note_misbehavior(&self, peer: PeerId, error: &SPVError) {
+        let points = match *error {
+            SPVError::SpvBadProofOfWork => 100,
+            SPVError::UnconnectedHeader => 20,
+            SPVError::BadMerkleRoot => 100,
+            SPVError::BadFilterLoad => 100,
+            SPVError::BadFilterType => 20,
+            SPVError::RequestRangeTooLarge => 20,
+            _ => 0
+        };
+        if points == 0 {
+            return;
+        }
+        if let Some(&addr) = self.peer_addrs.borrow().get(&peer) {
+            if let Ok(mut configdb) = self.configdb.lock() {
+                if let Ok(mut tx) = configdb.transaction() {
+                    if tx.add_misbehavior(&addr, points).is_ok() {
+                        let _ = tx.commit();
+                    }
+                }
+            }
+        }
+    }
+
+    // the p2p layer reports the round trip time of each ping it completes; persist it against the
+    // peer's addrman entry so get_a_peer's bias toward low-latency peers is backed by real data
+    fn note_latency(&self, peer: PeerId, ms: u32) {
+        if let Some(&addr) = self.peer_addrs.borrow().get(&peer) {
+            if let Ok(mut configdb) = self.configdb.lock() {
+                if let Ok(mut tx) = configdb.transaction() {
+                    if tx.update_speed(&addr, ms).is_ok() {
+                        let _ = tx.commit();
+                    }
+                }
+            }
+        }
+    }
+
     fn get_headers(&self, peer: PeerId, get: GetHeadersMessage) -> Result<(), SPVError> {
         let chaindb = self.chaindb.read().unwrap();
         for locator in get.locator_hashes.iter () {
             if chaindb.is_on_trunk(locator) {
                 let mut headers = Vec::with_capacity(2000);
                 for block_id in chaindb.iter_to_tip(locator).take(2000) {
-                    headers.push(LoneBlockHeader{header: chaindb.get_header(&block_id).unwrap().header, tx_count: VarInt(0)})
+                    headers.push(LoneBlockHeader{header: chaindb.get_header(&block_id).unwrap().header, tx_count: VarInt(0)});
+                    if block_id == get.hash_stop {
+                        break;
+                    }
                 }
                 if headers.len () > 0 {
                     self.p2p.send(P2PControl::Send(peer, NetworkMessage::Headers(headers)));
@@ -90,6 +398,25 @@ impl BlockServer {
         Ok(())
     }
 
+    // admit `len` more queued bytes for a peer, unless its outstanding byte budget is exhausted
+    fn reserve_bytes(&self, peer: PeerId, len: usize) -> bool {
+        let mut budgets = self.send_budget.borrow_mut();
+        let budget = budgets.entry(peer).or_insert(PeerSendBudget { bytes: 0 });
+
+        if budget.bytes + len > PEER_BYTE_CEILING {
+            false
+        } else {
+            budget.bytes += len;
+            true
+        }
+    }
+
+    // the p2p send thread reports how many bytes are actually still queued for a peer once it
+    // drains part of its backlog, replacing our own estimate with ground truth
+    fn note_send_queue_bytes(&self, peer: PeerId, bytes: usize) {
+        self.send_budget.borrow_mut().insert(peer, PeerSendBudget { bytes });
+    }
+
     fn get_blocks(&self, peer: PeerId, get: GetBlocksMessage) -> Result<(), SPVError> {
         let chaindb = self.chaindb.read().unwrap();
         for locator in get.locator_hashes.iter () {
@@ -98,7 +425,16 @@ impl BlockServer {
                     let header = chaindb.get_header(&block_id).unwrap();
                     if let Some(pref) = header.block {
                         let block = chaindb.fetch_block_by_ref(pref)?;
-                        self.p2p.send(P2PControl::Send(peer, NetworkMessage::Block(Block{header: header.header, txdata: block.txdata})));
+                        let block = Block{header: header.header, txdata: block.txdata};
+                        let size = encode::serialize(&block)?.len();
+                        if !self.reserve_bytes(peer, size) {
+                            // send queue for this peer is backed up, stop serving this batch until it drains
+                            break;
+                        }
+                        self.p2p.send(P2PControl::Send(peer, NetworkMessage::Block(block)));
+                    }
+                    if block_id == get.hash_stop {
+                        break;
                     }
                 }
                 break;
@@ -107,6 +443,104 @@ impl BlockServer {
         Ok(())
     }
 
+    // block ids of the trunk between start_height and stop_hash (inclusive), in increasing height order
+    fn filter_range(&self, start_height: u32, stop_hash: &Sha256dHash) -> Result<Vec<Sha256dHash>, SPVError> {
+        let chaindb = self.chaindb.read().unwrap();
+        let stop_height = chaindb.get_header(stop_hash).ok_or(SPVError::NoTip)?.height;
+        if stop_height.saturating_sub(start_height) >= MAX_FILTER_RANGE {
+            return Err(SPVError::RequestRangeTooLarge);
+        }
+
+        let mut ids = Vec::new();
+        let mut cursor = *stop_hash;
+        loop {
+            let stored = chaindb.get_header(&cursor).ok_or(SPVError::NoTip)?;
+            if stored.height < start_height {
+                break;
+            }
+            ids.push(cursor);
+            if stored.height == 0 {
+                break;
+            }
+            cursor = stored.header.prev_blockhash;
+        }
+        ids.reverse();
+        Ok(ids)
+    }
+
+    // reject anything but the two filter types BIP158 defines; we only ever compute and store these
+    fn check_filter_type(filter_type: u8) -> Result<(), SPVError> {
+        if filter_type == COIN_FILTER || filter_type == SCRIPT_FILTER {
+            Ok(())
+        } else {
+            Err(SPVError::BadFilterType)
+        }
+    }
+
+    fn get_cfilters(&self, peer: PeerId, get: GetCFilters) -> Result<(), SPVError> {
+        Self::check_filter_type(get.filter_type)?;
+        for block_id in self.filter_range(get.start_height, &get.stop_hash)? {
+            let chaindb = self.chaindb.read().unwrap();
+            let filter = chaindb.get_filter(&block_id, get.filter_type)?;
+            self.p2p.send(P2PControl::Send(peer, NetworkMessage::CFilter(CFilter {
+                filter_type: get.filter_type,
+                block_hash: block_id,
+                filter: filter.content
+            })));
+        }
+        Ok(())
+    }
+
+    fn get_cfheaders(&self, peer: PeerId, get: GetCFHeaders) -> Result<(), SPVError> {
+        Self::check_filter_type(get.filter_type)?;
+        let range = self.filter_range(get.start_height, &get.stop_hash)?;
+        if range.is_empty() {
+            // start_height is past stop_hash's height on this chain, nothing to serve
+            return Err(SPVError::NoTip);
+        }
+        let chaindb = self.chaindb.read().unwrap();
+
+        let previous_filter_header = if get.start_height == 0 {
+            Sha256dHash::default()
+        } else {
+            let prev = chaindb.get_header(&range[0]).ok_or(SPVError::NoTip)?.header.prev_blockhash;
+            chaindb.get_filter(&prev, get.filter_type)?.filter_header
+        };
+
+        let mut filter_hashes = Vec::with_capacity(range.len());
+        for block_id in range.iter() {
+            filter_hashes.push(chaindb.get_filter(block_id, get.filter_type)?.filter_hash);
+        }
+
+        self.p2p.send(P2PControl::Send(peer, NetworkMessage::CFHeaders(CFHeaders {
+            filter_type: get.filter_type,
+            stop_hash: get.stop_hash,
+            previous_filter_header,
+            filter_hashes
+        })));
+        Ok(())
+    }
+
+    fn get_cfcheckpt(&self, peer: PeerId, get: GetCFCheckpt) -> Result<(), SPVError> {
+        Self::check_filter_type(get.filter_type)?;
+        let range = self.filter_range(0, &get.stop_hash)?;
+        let chaindb = self.chaindb.read().unwrap();
+
+        let mut filter_headers = Vec::new();
+        for (height, block_id) in range.iter().enumerate() {
+            if height > 0 && height as u32 % CFCHECKPT_INTERVAL == 0 {
+                filter_headers.push(chaindb.get_filter(block_id, get.filter_type)?.filter_header);
+            }
+        }
+
+        self.p2p.send(P2PControl::Send(peer, NetworkMessage::CFCheckpt(CFCheckpt {
+            filter_type: get.filter_type,
+            stop_hash: get.stop_hash,
+            filter_headers
+        })));
+        Ok(())
+    }
+
     fn get_data(&self, peer: PeerId, get: Vec<Inventory>) -> Result<(), SPVError> {
         let chaindb = self.chaindb.read().unwrap();
         for inv in get {
@@ -114,10 +548,122 @@ impl BlockServer {
                 if let Some(header) = chaindb.get_header(&inv.hash) {
                     if let Some(pref) = header.block {
                         let block = chaindb.fetch_block_by_ref(pref)?;
-                        self.p2p.send(P2PControl::Send(peer, NetworkMessage::Block(Block{header: header.header, txdata: block.txdata})));
+                        let block = Block{header: header.header, txdata: block.txdata};
+                        let size = encode::serialize(&block)?.len();
+                        if !self.reserve_bytes(peer, size) {
+                            // send queue for this peer is backed up, stop serving this batch until it drains
+                            break;
+                        }
+                        if self.compact.borrow().contains_key(&peer) {
+                            self.send_cmpct_block(peer, &block)?;
+                        } else {
+                            self.p2p.send(P2PControl::Send(peer, NetworkMessage::Block(block)));
+                        }
                     }
                 }
             }
+            else if inv.inv_type == InvType::FilteredBlock {
+                if let Some(header) = chaindb.get_header(&inv.hash) {
+                    if let Some(pref) = header.block {
+                        let block = chaindb.fetch_block_by_ref(pref)?;
+                        let block = Block{header: header.header, txdata: block.txdata};
+                        let size = encode::serialize(&block)?.len();
+                        if !self.reserve_bytes(peer, size) {
+                            break;
+                        }
+                        self.send_merkleblock(peer, block)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // BIP37: test the peer's bloom filter against every transaction and send back a MerkleBlock
+    fn send_merkleblock(&self, peer: PeerId, block: Block) -> Result<(), SPVError> {
+        let mut filters = self.filters.borrow_mut();
+        if let Some(filter) = filters.get_mut(&peer) {
+            let matched: Vec<bool> = block.txdata.iter().map(|tx| filter.matches(tx)).collect();
+            let merkle_block = MerkleBlock::from_block_matches(&block, &matched)
+                .map_err(|_| SPVError::BadMerkleRoot)?;
+            self.p2p.send(P2PControl::Send(peer, NetworkMessage::MerkleBlock(merkle_block)));
+        }
+        Ok(())
+    }
+
+    fn filter_load(&self, peer: PeerId, load: FilterLoad) -> Result<(), SPVError> {
+        if load.hash_funcs > MAX_BLOOM_HASH_FUNCS || load.filter.len() > MAX_BLOOM_FILTER_SIZE {
+            return Err(SPVError::BadFilterLoad);
+        }
+        self.filters.borrow_mut().insert(peer, BloomFilter::new(&load));
+        Ok(())
+    }
+
+    fn filter_add(&self, peer: PeerId, add: FilterAdd) -> Result<(), SPVError> {
+        if add.data.len() > MAX_FILTER_ADD_DATA_SIZE {
+            return Err(SPVError::BadFilterLoad);
+        }
+        if let Some(filter) = self.filters.borrow_mut().get_mut(&peer) {
+            filter.insert(&add.data);
+        }
+        Ok(())
+    }
+
+    fn filter_clear(&self, peer: PeerId) -> Result<(), SPVError> {
+        self.filters.borrow_mut().remove(&peer);
+        Ok(())
+    }
+
+    fn send_cmpct(&self, peer: PeerId, cmpct: SendCmpct) -> Result<(), SPVError> {
+        if cmpct.send_compact {
+            self.compact.borrow_mut().insert(peer, CompactPeerState { version: cmpct.version, high_bandwidth: true });
+        } else {
+            self.compact.borrow_mut().remove(&peer);
+        }
+        Ok(())
+    }
+
+    // BIP152: announce a block as a header, short transaction ids and a prefilled coinbase
+    fn send_cmpct_block(&self, peer: PeerId, block: &Block) -> Result<(), SPVError> {
+        let version = self.compact.borrow().get(&peer).map(|s| s.version).unwrap_or(1);
+        let nonce = rand::thread_rng().next_u64();
+        let (k0, k1) = short_id_keys(&block.header, nonce);
+
+        let mut short_ids = Vec::with_capacity(block.txdata.len().saturating_sub(1));
+        let mut prefilled_txs = Vec::new();
+        for (i, tx) in block.txdata.iter().enumerate() {
+            if i == 0 {
+                // the coinbase can't be reconstructed from mempool, always prefill it
+                prefilled_txs.push(PrefilledTransaction { index: VarInt(0), tx: tx.clone() });
+                continue;
+            }
+            short_ids.push(short_id(version, k0, k1, tx));
+        }
+
+        self.p2p.send(P2PControl::Send(peer, NetworkMessage::CmpctBlock(CmpctBlock {
+            compact_block: HeaderAndShortIds {
+                header: block.header,
+                nonce,
+                short_ids,
+                prefilled_txs
+            }
+        })));
+        Ok(())
+    }
+
+    fn get_block_txn(&self, peer: PeerId, get: GetBlockTxn) -> Result<(), SPVError> {
+        let chaindb = self.chaindb.read().unwrap();
+        let req: BlockTransactionsRequest = get.txs_request;
+        if let Some(header) = chaindb.get_header(&req.block_hash) {
+            if let Some(pref) = header.block {
+                let block = chaindb.fetch_block_by_ref(pref)?;
+                let transactions = req.indexes.iter()
+                    .filter_map(|&i| block.txdata.get(i as usize).cloned())
+                    .collect();
+                self.p2p.send(P2PControl::Send(peer, NetworkMessage::BlockTxn(BlockTxn {
+                    transactions: BlockTransactions { block_hash: req.block_hash, transactions }
+                })));
+            }
         }
         Ok(())
     }
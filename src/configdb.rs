@@ -34,12 +34,71 @@ use std::{
 };
 
 use rand;
-use rand::RngCore;
+use rand::{Rng, RngCore};
 
 use std::sync::{Arc, Mutex};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 pub type SharedConfigDB = Arc<Mutex<ConfigDB>>;
 
+// addrman-style bucketing, loosely following Bitcoin Core's CAddrMan
+const NEW_BUCKET_COUNT: u32 = 1024;
+const TRIED_BUCKET_COUNT: u32 = 256;
+const BUCKET_SLOTS: u32 = 64;
+// percentage of get_a_peer draws that come from the tried set rather than new
+const TRIED_CHANCE_PERCENT: u32 = 60;
+// a peer is banned once its accumulated misbehavior points cross this threshold
+const MISBEHAVIOR_BAN_THRESHOLD: i64 = 100;
+
+// a stable string encoding of an address, used as the sqlite key
+fn addr_to_hex(address: &Address) -> String {
+    let mut s = String::new();
+    for d in address.address.iter() {
+        s.push_str(format!("{:4x}", d).as_str());
+    }
+    s
+}
+
+fn hex_to_address(hex: &str, port: u16, services: u64) -> Address {
+    let mut tail = hex;
+    let mut v = [0u16; 8];
+    for i in 0..8 {
+        let (digit, t) = tail.split_at(4);
+        tail = t;
+        v[i] = u16::from_str_radix(digit, 16).unwrap_or(0);
+    }
+    Address { address: v, port, services }
+}
+
+// the /16-equivalent group an address belongs to, used to spread a single source across buckets
+fn group(address: &Address) -> [u16; 2] {
+    // IPv4 addresses are stored IPv4-mapped (::ffff:a.b.c.d), their /16 is the 7th segment
+    if address.address[..6] == [0, 0, 0, 0, 0, 0xffff] {
+        [0xffff, address.address[6]]
+    } else {
+        [address.address[0], address.address[1]]
+    }
+}
+
+fn keyed_hasher(key: &[u8]) -> DefaultHasher {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher
+}
+
+// Bitcoin Core's addrman considers an entry not seen in 30 days stale enough to discard
+// regardless of what's competing for its slot (see CAddrInfo::IsTerrible)
+const OCCUPANT_STALE_SECS: u32 = 30 * 24 * 60 * 60;
+
+// whether the entry currently occupying a bucket/slot is bad enough to evict in favor of a newly
+// seen candidate: still banned, already flagged as misbehaving, or stale on its own terms - never
+// just "older than the candidate", since a freshly heard address is always "now" and that would
+// let an attacker evict any occupant merely by relaying addresses with a current timestamp
+fn occupant_is_worse(banned_until: u32, misbehavior: i64, last_seen: u32, now: u32) -> bool {
+    banned_until > now || misbehavior > 0 || now.saturating_sub(last_seen) > OCCUPANT_STALE_SECS
+}
+
 pub struct ConfigDB {
     conn: Connection
 }
@@ -97,17 +156,43 @@ impl<'a> ConfigTX<'a> {
     ///   * header - block header
     ///   * tx - transactions
     ///   * blk_tx - n:m mapping of header to transactions to form a block.
-    ///   * peers - list of known peers
+    ///   * addr_new - addresses we heard of but never successfully connected to, addrman-style bucketed
+    ///   * addr_tried - addresses we successfully connected to at least once, addrman-style bucketed
+    ///   * addrman_key - random key mixed into the bucket hash so the bucketing can't be predicted from outside
     pub fn create_tables(&mut self) -> Result<(), SPVError> {
         trace!("creating tables...");
         self.dirty.set(true);
 
-        self.tx.execute("create table if not exists peers (
+        self.tx.execute("create table if not exists addrman_key (key blob)", &[])?;
+        if self.addrman_key().is_err() {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            self.tx.execute("insert into addrman_key (key) values (?)", &[&key.to_vec()])?;
+        }
+
+        self.tx.execute("create table if not exists addr_new (
+                                address text,
+                                port integer,
+                                services integer,
+                                last_seen integer,
+                                banned_until integer,
+                                speed integer,
+                                misbehavior integer default 0,
+                                bucket integer,
+                                slot integer,
+                                unique(bucket, slot))", &[])?;
+
+        self.tx.execute("create table if not exists addr_tried (
                                 address text primary key,
                                 port integer,
                                 services integer,
                                 last_seen integer,
-                                banned_until integer)", &[])?;
+                                banned_until integer,
+                                speed integer,
+                                misbehavior integer default 0,
+                                bucket integer,
+                                slot integer,
+                                unique(bucket, slot))", &[])?;
 
 
         self.tx.execute("create table if not exists birth (inception integer)", &[])?;
@@ -132,25 +217,144 @@ impl<'a> ConfigTX<'a> {
                              })?)
     }
 
-    /// store a peer
+    fn addrman_key(&self) -> Result<Vec<u8>, SPVError> {
+        Ok(self.tx.query_row("select key from addrman_key", &[], |row| row.get(0))?)
+    }
+
+    fn new_bucket(&self, key: &[u8], addr: &Address, source: &Address) -> Result<(u32, u32), SPVError> {
+        let mut h = keyed_hasher(key);
+        "new".hash(&mut h);
+        group(addr).hash(&mut h);
+        group(source).hash(&mut h);
+        let bucket = (h.finish() % NEW_BUCKET_COUNT as u64) as u32;
+
+        let mut h = keyed_hasher(key);
+        bucket.hash(&mut h);
+        addr_to_hex(addr).hash(&mut h);
+        let slot = (h.finish() % BUCKET_SLOTS as u64) as u32;
+        Ok((bucket, slot))
+    }
+
+    fn tried_bucket(&self, key: &[u8], addr: &Address) -> Result<(u32, u32), SPVError> {
+        let mut h = keyed_hasher(key);
+        "tried".hash(&mut h);
+        group(addr).hash(&mut h);
+        let bucket = (h.finish() % TRIED_BUCKET_COUNT as u64) as u32;
+
+        let mut h = keyed_hasher(key);
+        bucket.hash(&mut h);
+        addr_to_hex(addr).hash(&mut h);
+        let slot = (h.finish() % BUCKET_SLOTS as u64) as u32;
+        Ok((bucket, slot))
+    }
+
+    /// store a peer we heard of into the "new" set, bucketed by its /16 group and the group of
+    /// whoever told us about it, so a single source can only ever occupy a bounded number of slots
     ///   * last_seen - in unix epoch seconds
-    ///   * banned_until - in unix epoch seconds
-    ///   * speed - in ms as measured with ping
-    pub fn store_peer (&mut self, address: &Address, last_seen: u32, banned_until: u32) -> Result<(), SPVError> {
+    pub fn store_peer (&mut self, address: &Address, source: &Address, last_seen: u32) -> Result<(), SPVError> {
         self.dirty.set(true);
-        let mut s = String::new();
-        for d in address.address.iter() {
-            s.push_str(format!("{:4x}",d).as_str());
+        let s = addr_to_hex(address);
+
+        // already vetted and promoted, just refresh last_seen there
+        let tried: Result<i64, Error> = self.tx.query_row(
+            "select rowid from addr_tried where address = ?", &[&s], |row| row.get(0));
+        if tried.is_ok() {
+            self.tx.execute("update addr_tried set last_seen = ? where address = ?", &[&last_seen, &s])?;
+            return Ok(());
+        }
+
+        let key = self.addrman_key()?;
+        let (bucket, slot) = self.new_bucket(&key, address, source)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+
+        // only displace whatever currently occupies that bucket/slot if it's actually worse than
+        // this candidate - otherwise drop the new address rather than clobber a vetted entry
+        let occupant: Result<(String, u32, u32, i64), Error> = self.tx.query_row(
+            "select address, last_seen, banned_until, coalesce(misbehavior, 0) from addr_new \
+             where bucket = ? and slot = ?", &[&bucket, &slot],
+            |row| (row.get(0), row.get(1), row.get(2), row.get(3)));
+        if let Ok((occupant_addr, occupant_last_seen, occupant_banned_until, occupant_misbehavior)) = occupant {
+            if occupant_addr != s &&
+                !occupant_is_worse(occupant_banned_until, occupant_misbehavior, occupant_last_seen, now) {
+                return Ok(());
+            }
+            self.tx.execute("delete from addr_new where bucket = ? and slot = ?", &[&bucket, &slot])?;
         }
+        self.tx.execute("delete from addr_new where address = ?", &[&s])?;
+        self.tx.execute("insert into addr_new (address, port, services, last_seen, banned_until, bucket, slot) \
+                    values (?, ?, ?, ?, 0, ?, ?)",
+                    &[&s, &address.port, &(address.services as i64), &last_seen, &bucket, &slot])?;
+        Ok(())
+    }
+
+    /// promote an address to the "tried" set after a successful connection
+    pub fn promote_to_tried (&mut self, addr: &SocketAddr) -> Result<(), SPVError> {
+        self.dirty.set(true);
+        let address = Address::new(addr, 0);
+        let s = addr_to_hex(&address);
+
+        let row: Result<(u16, i64, u32), Error> = self.tx.query_row(
+            "select port, services, last_seen from addr_new where address = ?", &[&s],
+            |row| (row.get(0), row.get(1), row.get(2)));
+        let (port, services, last_seen) = match row {
+            Ok(r) => r,
+            Err(_) => return Ok(()) // nothing known about this address, nothing to promote
+        };
+
+        let key = self.addrman_key()?;
+        let peer = Address { address: address.address, port, services: services as u64 };
+        let (bucket, slot) = self.tried_bucket(&key, &peer)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
 
-        let row: Result<i64, Error> = self.tx.query_row(
-            "select rowid from peers where address = ?", &[&s], | row | { row.get(0) });
-        if let Ok (r) = row {
-            self.tx.execute("update peers set last_seen = ? where rowid = ?", &[&last_seen, &r])?;
+        // a successful connection is good evidence, but still don't clobber a tried entry that's
+        // actively better than the one we just connected to
+        let occupant: Result<(String, u32, u32, i64), Error> = self.tx.query_row(
+            "select address, last_seen, banned_until, coalesce(misbehavior, 0) from addr_tried \
+             where bucket = ? and slot = ?", &[&bucket, &slot],
+            |row| (row.get(0), row.get(1), row.get(2), row.get(3)));
+        if let Ok((occupant_addr, occupant_last_seen, occupant_banned_until, occupant_misbehavior)) = occupant {
+            if occupant_addr != s &&
+                !occupant_is_worse(occupant_banned_until, occupant_misbehavior, occupant_last_seen, now) {
+                self.tx.execute("delete from addr_new where address = ?", &[&s])?;
+                return Ok(());
+            }
         }
-        else {
-            self.tx.execute("insert into peers (address, port, services, last_seen, banned_until) \
-                        values (?, ?, ?, ?, ?)", &[&s, &address.port, &(address.services as i64), &last_seen, &banned_until])?;
+
+        self.tx.execute("delete from addr_new where address = ?", &[&s])?;
+        self.tx.execute("delete from addr_tried where bucket = ? and slot = ?", &[&bucket, &slot])?;
+        self.tx.execute("insert or replace into addr_tried (address, port, services, last_seen, banned_until, bucket, slot) \
+                    values (?, ?, ?, ?, 0, ?, ?)",
+                    &[&s, &port, &services, &last_seen, &bucket, &slot])?;
+        Ok(())
+    }
+
+    /// record the round trip time of the last ping to a peer, in milliseconds
+    pub fn update_speed(&mut self, addr: &SocketAddr, ms: u32) -> Result<(), SPVError> {
+        self.dirty.set(true);
+        let address = Address::new(addr, 0);
+        let s = addr_to_hex(&address);
+        self.tx.execute("update addr_new set speed = ? where address = ?", &[&ms, &s])?;
+        self.tx.execute("update addr_tried set speed = ? where address = ?", &[&ms, &s])?;
+        Ok(())
+    }
+
+    /// add misbehavior points to a peer, banning it once the accumulated total crosses the threshold
+    pub fn add_misbehavior(&mut self, addr: &SocketAddr, points: u32) -> Result<(), SPVError> {
+        self.dirty.set(true);
+        let address = Address::new(addr, 0);
+        let s = addr_to_hex(&address);
+        self.tx.execute("update addr_new set misbehavior = coalesce(misbehavior, 0) + ? where address = ?", &[&points, &s])?;
+        self.tx.execute("update addr_tried set misbehavior = coalesce(misbehavior, 0) + ? where address = ?", &[&points, &s])?;
+
+        let total: i64 = self.tx.query_row(
+            "select coalesce(max(misbehavior), 0) from (
+                select misbehavior from addr_new where address = ?
+                union all
+                select misbehavior from addr_tried where address = ?)",
+            &[&s, &s], |row| row.get(0))?;
+
+        if total >= MISBEHAVIOR_BAN_THRESHOLD {
+            self.ban(addr)?;
         }
         Ok(())
     }
@@ -159,54 +363,66 @@ impl<'a> ConfigTX<'a> {
     pub fn ban (&mut self, addr: &SocketAddr) -> Result<i32, SPVError> {
         self.dirty.set(true);
         let address = Address::new (addr, 0);
-        let mut s = String::new();
-        for d in address.address.iter() {
-            s.push_str(format!("{:4x}",d).as_str());
-        }
-        let banned_until = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32 + 2*24*60;
-        Ok(self.tx.execute("update peers set banned_until = ? where address = ?", &[&banned_until, &s])?)
+        let s = addr_to_hex(&address);
+        let banned_until = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32 + 2*24*60*60;
+        self.tx.execute("update addr_new set banned_until = ? where address = ?", &[&banned_until, &s])?;
+        Ok(self.tx.execute("update addr_tried set banned_until = ? where address = ?", &[&banned_until, &s])?)
     }
 
     #[allow(unused)]
     pub fn remove_peer (&mut self, addr: &SocketAddr) -> Result<i32, SPVError> {
         self.dirty.set(true);
         let address = Address::new (addr, 0);
-        let mut s = String::new();
-        for d in address.address.iter() {
-            s.push_str(format!("{:4x}",d).as_str());
-        }
-        Ok(self.tx.execute("delete from peers where address = ?", &[&s])?)
+        let s = addr_to_hex(&address);
+        self.tx.execute("delete from addr_new where address = ?", &[&s])?;
+        Ok(self.tx.execute("delete from addr_tried where address = ?", &[&s])?)
     }
 
-    /// get a random stored peer
-    pub fn get_a_peer (&self, earlier: &HashSet<SocketAddr>) -> Result<Address, SPVError> {
-        let n_peers: i64 = self.tx.query_row(
-            "select count(*) from peers", &[], | row | { row.get(0) })?;
+    // draw one address from a bucket with probability proportional to its quality, rather than
+    // deterministically returning the single best row - so a bucket with several good peers
+    // doesn't always hand back the same one
+    fn weighted_pick(&self, table: &str, bucket: u32, now: u32) -> Result<Option<(String, u16, i64)>, SPVError> {
+        let query = format!(
+            "select address, port, services, coalesce(misbehavior, 0), speed from {} \
+             where bucket = ? and banned_until < ?", table);
+        let mut stmt = self.tx.prepare(&query)?;
+        let rows: Vec<(String, u16, i64, i64, Option<i64>)> = stmt.query_map(&[&bucket, &now], |row| {
+            (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4))
+        })?.filter_map(|r| r.ok()).collect();
+
+        if rows.is_empty() {
+            return Ok(None);
+        }
+
+        let weights: Vec<f64> = rows.iter().map(|&(_, _, _, misbehavior, speed)| {
+            let speed = speed.unwrap_or(500) as f64;
+            1.0 / (1.0 + misbehavior as f64) / (1.0 + speed / 100.0)
+        }).collect();
+        let total: f64 = weights.iter().sum();
 
-        if n_peers == 0 {
-            return Err(SPVError::NoPeers);
+        let mut draw = rand::thread_rng().gen::<f64>() * total;
+        for ((address, port, services, _, _), weight) in rows.into_iter().zip(weights.into_iter()) {
+            if draw < weight {
+                return Ok(Some((address, port, services)));
+            }
+            draw -= weight;
         }
+        Ok(None)
+    }
 
+    /// get a stored peer, biased toward the "tried" set and, within a bucket, toward peers with
+    /// low ping latency and few misbehavior points
+    pub fn get_a_peer (&self, earlier: &HashSet<SocketAddr>) -> Result<Address, SPVError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
         let mut rng = rand::thread_rng();
+
         for _ in 0 .. 100 { // give up after 100 attempts
-            let rowid = (rng.next_u64() as i64) % n_peers + 1;
-            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
-            let address:Result<(String, u16, i64), Error> = self.tx.query_row(
-                "select address, port, services from peers where rowid = ? and banned_until < ? ", &[&(rowid as i64), &now], |row| {
-                    (row.get(0), row.get(1), row.get(2) ) });
-            if let Ok(a) = address {
-                let mut tail = a.0.as_str();
-                let mut v = [0u16; 8];
-                for i in 0..8 {
-                    let (digit, mut t) = tail.split_at(4);
-                    tail = t;
-                    v [i] = u16::from_str_radix(digit, 16).unwrap_or(0);
-                }
-                let peer = Address {
-                    address: v,
-                    port: a.1,
-                    services: a.2 as u64
-                };
+            let use_tried = rng.next_u32() % 100 < TRIED_CHANCE_PERCENT;
+            let table = if use_tried { "addr_tried" } else { "addr_new" };
+            let bucket = rng.next_u32() % if use_tried { TRIED_BUCKET_COUNT } else { NEW_BUCKET_COUNT };
+
+            if let Some((hex, port, services)) = self.weighted_pick(table, bucket, now)? {
+                let peer = hex_to_address(&hex, port, services as u64);
                 if let Ok(addr) = peer.socket_addr() {
                     if !earlier.contains(&addr) {
                         return Ok(peer)
@@ -43,6 +43,12 @@ pub enum SPVError {
     UnknownUTXO,
     /// Merkle root of block does not match the header
     BadMerkleRoot,
+    /// a BIP37 filterload/filteradd violated the protocol's size limits
+    BadFilterLoad,
+    /// requested BIP158 filter type is not one of the types we serve
+    BadFilterType,
+    /// requested range of blocks/filters is larger than we're willing to walk in one request
+    RequestRangeTooLarge,
     /// downstream error
     Downstream(String),
     /// Network IO error
@@ -66,6 +72,9 @@ impl Error for SPVError {
             SPVError::UnknownUTXO => "unknown utxo",
             SPVError::NoPeers => "no peers",
             SPVError::BadMerkleRoot => "merkle root of header does not match transaction list",
+            SPVError::BadFilterLoad => "bloom filter parameters exceed BIP37 limits",
+            SPVError::BadFilterType => "unsupported filter type",
+            SPVError::RequestRangeTooLarge => "requested range too large",
             SPVError::Downstream(ref s) => s,
             SPVError::IO(ref err) => err.description(),
             SPVError::DB(ref err) => err.description(),
@@ -84,6 +93,9 @@ impl Error for SPVError {
             SPVError::UnknownUTXO => None,
             SPVError::Downstream(_) => None,
             SPVError::BadMerkleRoot => None,
+            SPVError::BadFilterLoad => None,
+            SPVError::BadFilterType => None,
+            SPVError::RequestRangeTooLarge => None,
             SPVError::IO(ref err) => Some(err),
             SPVError::DB(ref err) => Some(err),
             SPVError::Util(ref err) => Some(err),
@@ -102,6 +114,7 @@ impl fmt::Display for SPVError {
             SPVError::UnconnectedHeader |
             SPVError::NoTip |
             SPVError::NoPeers | SPVError::BadMerkleRoot |
+            SPVError::BadFilterLoad | SPVError::BadFilterType | SPVError::RequestRangeTooLarge |
             SPVError::UnknownUTXO => write!(f, "{}", self.description()),
             SPVError::Downstream(ref s) => write!(f, "{}", s),
             SPVError::IO(ref err) => write!(f, "IO error: {}", err),